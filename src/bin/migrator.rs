@@ -0,0 +1,89 @@
+use clap::{Parser, Subcommand};
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
+
+#[derive(Parser)]
+#[command(name = "migrator", about = "Apply, revert, and inspect database migrations")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply all pending migrations
+    Up,
+    /// Revert the most recently applied migration
+    Down,
+    /// Revert the most recently applied migration, then apply every pending
+    /// migration (the reverted one included)
+    Redo,
+    /// Show which migrations are applied vs pending
+    List,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    dotenvy::dotenv().ok();
+    tracing_subscriber::fmt::init();
+
+    let cli = Cli::parse();
+    let database_uri =
+        std::env::var("DATABASE_URL").expect("DATABASE_URL environment variable not set");
+
+    let mut conn = PgConnection::establish(&database_uri)
+        .map_err(|e| anyhow::format_err!("failed to connect to database: {e}"))?;
+
+    match cli.command {
+        Command::Up => {
+            let applied = conn
+                .run_pending_migrations(MIGRATIONS)
+                .map_err(|e| anyhow::format_err!("failed to apply migrations: {e}"))?;
+            for migration in applied {
+                println!("applied {migration}");
+            }
+        }
+        Command::Down => {
+            let reverted = conn
+                .revert_last_migration(MIGRATIONS)
+                .map_err(|e| anyhow::format_err!("failed to revert migration: {e}"))?;
+            println!("reverted {reverted}");
+        }
+        Command::Redo => {
+            let reverted = conn
+                .revert_last_migration(MIGRATIONS)
+                .map_err(|e| anyhow::format_err!("failed to revert migration: {e}"))?;
+            println!("reverted {reverted}");
+
+            let applied = conn
+                .run_pending_migrations(MIGRATIONS)
+                .map_err(|e| anyhow::format_err!("failed to apply migrations: {e}"))?;
+            for migration in applied {
+                println!("applied {migration}");
+            }
+        }
+        Command::List => {
+            let applied: std::collections::HashSet<_> = conn
+                .applied_migrations()
+                .map_err(|e| anyhow::format_err!("failed to list applied migrations: {e}"))?
+                .into_iter()
+                .collect();
+            for migration in MIGRATIONS
+                .migrations()
+                .map_err(|e| anyhow::format_err!("failed to list migrations: {e}"))?
+            {
+                let name = migration.name();
+                let marker = if applied.contains(&name.version().as_owned()) {
+                    "x"
+                } else {
+                    " "
+                };
+                println!("[{marker}] {name}");
+            }
+        }
+    }
+
+    Ok(())
+}
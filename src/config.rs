@@ -1,38 +1,225 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
 use serde::Deserialize;
 
+use crate::storage::schema;
+
+/// The resolved set of ESI credentials and operational knobs, as produced by
+/// whichever [`ConfigSource`] is active. This is the part of configuration
+/// that can legitimately change at runtime (secret rotation, scope changes,
+/// tuning poll intervals) without restarting the process.
 #[derive(Debug, Clone, Deserialize)]
-pub struct Config {
+pub struct ConfigValues {
     pub application_id: String,
     pub application_secret: String,
     pub redirect_uri: String,
+    pub poll_interval_secs: u64,
+    /// SMTP settings for `notifier::EmailNotifier`. All four are optional
+    /// and the feature is a no-op unless every one of them is set.
+    #[serde(default)]
+    pub smtp_host: Option<String>,
+    #[serde(default)]
+    pub smtp_user: Option<String>,
+    #[serde(default)]
+    pub smtp_pass: Option<String>,
+    #[serde(default)]
+    pub from_addr: Option<String>,
+}
+
+/// A place `ConfigValues` can be loaded from. `Config::from_db` polls its
+/// source on a timer so operators can rotate the ESI secret or change
+/// scopes without a redeploy.
+pub trait ConfigSource {
+    fn load(&self) -> Result<ConfigValues, anyhow::Error>;
+}
+
+pub struct EnvSource;
+
+impl ConfigSource for EnvSource {
+    fn load(&self) -> Result<ConfigValues, anyhow::Error> {
+        let application_id = std::env::var("MKB_ESI_APPLICATION_ID")
+            .map_err(|_| anyhow::format_err!("MKB_ESI_APPLICATION_ID environment variable not set"))?;
+        let application_secret = std::env::var("MKB_ESI_APPLICATION_SECRET").map_err(|_| {
+            anyhow::format_err!("MKB_ESI_APPLICATION_SECRET environment variable not set")
+        })?;
+        let redirect_uri = std::env::var("MKB_ESI_REDIRECT_URI")
+            .map_err(|_| anyhow::format_err!("MKB_ESI_REDIRECT_URI environment variable not set"))?;
+        let poll_interval_secs = std::env::var("MKB_POLL_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(600);
+
+        Ok(ConfigValues {
+            application_id,
+            application_secret,
+            redirect_uri,
+            poll_interval_secs,
+            smtp_host: std::env::var("MKB_SMTP_HOST").ok(),
+            smtp_user: std::env::var("MKB_SMTP_USER").ok(),
+            smtp_pass: std::env::var("MKB_SMTP_PASS").ok(),
+            from_addr: std::env::var("MKB_SMTP_FROM_ADDR").ok(),
+        })
+    }
+}
+
+pub struct FileSource {
+    pub path: String,
+}
+
+impl ConfigSource for FileSource {
+    fn load(&self) -> Result<ConfigValues, anyhow::Error> {
+        let file_content = std::fs::read_to_string(&self.path)
+            .map_err(|e| anyhow::format_err!("Failed to read config file: {e}"))?;
+        serde_json::from_str(&file_content)
+            .map_err(|e| anyhow::format_err!("Failed to parse config file: {e}"))
+    }
+}
+
+/// Reads configuration from the `config` table of the same Postgres database
+/// the crate already connects to, stored as `key`/`value` rows.
+pub struct DbSource {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl DbSource {
+    pub fn new(pool: Pool<ConnectionManager<PgConnection>>) -> Self {
+        Self { pool }
+    }
+
+    fn required(
+        rows: &std::collections::HashMap<String, String>,
+        key: &str,
+    ) -> Result<String, anyhow::Error> {
+        rows.get(key)
+            .cloned()
+            .ok_or_else(|| anyhow::format_err!("missing `{key}` in config table"))
+    }
+}
+
+impl ConfigSource for DbSource {
+    fn load(&self) -> Result<ConfigValues, anyhow::Error> {
+        let mut conn = self.pool.get()?;
+        let rows: std::collections::HashMap<String, String> = schema::config::dsl::config
+            .select((schema::config::key, schema::config::value))
+            .load::<(String, String)>(&mut conn)?
+            .into_iter()
+            .collect();
+
+        Ok(ConfigValues {
+            application_id: Self::required(&rows, "application_id")?,
+            application_secret: Self::required(&rows, "application_secret")?,
+            redirect_uri: Self::required(&rows, "redirect_uri")?,
+            poll_interval_secs: rows
+                .get("poll_interval_secs")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(600),
+            smtp_host: rows.get("smtp_host").cloned(),
+            smtp_user: rows.get("smtp_user").cloned(),
+            smtp_pass: rows.get("smtp_pass").cloned(),
+            from_addr: rows.get("from_addr").cloned(),
+        })
+    }
+}
+
+/// The resolved configuration for the running process. `database_uri` is
+/// fixed at startup (it's needed to open the connection a `DbSource` would
+/// itself read from), while everything else lives behind an
+/// `Arc<ArcSwap<ConfigValues>>` so `EsiClient` and `AppState` always observe
+/// the latest snapshot, including one swapped in by a background reload.
+#[derive(Clone)]
+pub struct Config {
     pub database_uri: String,
+    values: Arc<ArcSwap<ConfigValues>>,
 }
 
 impl Config {
     pub fn from_env() -> Self {
-        let application_id = std::env::var("MKB_ESI_APPLICATION_ID")
-            .expect("MKB_ESI_APPLICATION_ID environment variable not set");
-        let application_secret = std::env::var("MKB_ESI_APPLICATION_SECRET")
-            .expect("MKB_ESI_APPLICATION_SECRET environment variable not set");
-        let redirect_uri = std::env::var("MKB_ESI_REDIRECT_URI")
-            .expect("MKB_ESI_REDIRECT_URI environment variable not set");
         let database_uri =
             std::env::var("DATABASE_URL").expect("DATABASE_URL environment variable not set");
+        let values = EnvSource
+            .load()
+            .expect("failed to load configuration from environment");
 
         Self {
-            application_id,
-            application_secret,
-            redirect_uri,
             database_uri,
+            values: Arc::new(ArcSwap::from_pointee(values)),
         }
     }
 
     pub fn _from_file() -> Result<Self, anyhow::Error> {
         let path = std::env::var("MKB_CONFIG_PATH").unwrap_or_else(|_| "config.json".to_string());
-        let file_content = std::fs::read_to_string(path).expect("Failed to read config file");
-        match serde_json::from_str(&file_content) {
-            Ok(config) => Ok(config),
-            _ => Err(anyhow::format_err!("Failed to parse config file")),
-        }
+        let database_uri =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL environment variable not set");
+        let values = FileSource { path }.load()?;
+
+        Ok(Self {
+            database_uri,
+            values: Arc::new(ArcSwap::from_pointee(values)),
+        })
+    }
+
+    /// Builds configuration from the `config` table and spawns a background
+    /// task that re-reads it every `refresh_interval`, swapping in any
+    /// changes atomically. Must be called from within a Tokio runtime.
+    pub fn from_db(
+        database_uri: String,
+        pool: Pool<ConnectionManager<PgConnection>>,
+        refresh_interval: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let source = Arc::new(DbSource::new(pool));
+        let values = Arc::new(ArcSwap::from_pointee(source.load()?));
+
+        let reload_values = values.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            interval.tick().await; // first tick fires immediately; we already loaded above
+            loop {
+                interval.tick().await;
+                // `DbSource::load` checks out a blocking r2d2 connection and
+                // runs a synchronous Diesel query, so it's offloaded to the
+                // blocking pool rather than run directly on this task, which
+                // would otherwise stall a Tokio worker thread every tick.
+                let source = source.clone();
+                match tokio::task::spawn_blocking(move || source.load()).await {
+                    Ok(Ok(fresh)) => reload_values.store(Arc::new(fresh)),
+                    Ok(Err(e)) => {
+                        tracing::error!(error = e.to_string(), "failed to reload config from db")
+                    }
+                    Err(e) => {
+                        tracing::error!(error = e.to_string(), "config reload task panicked")
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            database_uri,
+            values,
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<ConfigValues> {
+        self.values.load_full()
+    }
+
+    pub fn application_id(&self) -> String {
+        self.values.load().application_id.clone()
+    }
+
+    pub fn application_secret(&self) -> String {
+        self.values.load().application_secret.clone()
+    }
+
+    pub fn redirect_uri(&self) -> String {
+        self.values.load().redirect_uri.clone()
+    }
+
+    pub fn poll_interval_secs(&self) -> u64 {
+        self.values.load().poll_interval_secs
     }
 }
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::queue::JobQueue;
 use crate::storage::models;
 use crate::{config::Config, storage::models::Killmail};
 use base64::Engine;
+use dashmap::DashMap;
 use jsonwebtoken::{Algorithm, DecodingKey, TokenData, Validation, decode, jwk::Jwk};
 use reqwest::Response;
 use serde::{Deserialize, Serialize};
@@ -22,10 +25,12 @@ pub struct Claims {
 
 #[derive(Clone)]
 pub struct EsiClient {
-    app_id: String,
-    app_secret: String,
-    redirect_uri: String,
-    jobs_sender: tokio::sync::mpsc::Sender<processor::Job>,
+    config: Config,
+    queue: JobQueue,
+    /// Caches resolved `/universe/names/` lookups for the lifetime of the
+    /// process - names and categories are effectively immutable, so entries
+    /// never need invalidating.
+    entity_cache: Arc<DashMap<i64, models::Entity>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -34,15 +39,25 @@ struct KillmailItem {
     killmail_id: i64,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct NameItem {
+    category: String,
+    id: i64,
+    name: String,
+}
+
 impl EsiClient {
-    pub fn from_config(sender: &tokio::sync::mpsc::Sender<processor::Job>, config: Config) -> Self {
+    pub fn from_config(queue: JobQueue, config: Config) -> Self {
         Self {
-            app_id: config.application_id,
-            app_secret: config.application_secret,
-            redirect_uri: config.redirect_uri,
-            jobs_sender: sender.clone(),
+            config,
+            queue,
+            entity_cache: Arc::new(DashMap::new()),
         }
     }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 }
 
 pub enum Token {
@@ -53,10 +68,12 @@ pub enum Token {
 impl EsiClient {
     pub fn build_auth_url(&self) -> (String, String) {
         let nonce = Uuid::new_v4().to_string();
+        let app_id = self.config.application_id();
+        let redirect_uri = self.config.redirect_uri();
         let params = HashMap::from([
             ("response_type", "code"),
-            ("client_id", self.app_id.as_str()),
-            ("redirect_uri", self.redirect_uri.as_str()),
+            ("client_id", app_id.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
             (
                 "scope",
                 "publicData esi-killmails.read_killmails.v1 esi-killmails.read_corporation_killmails.v1",
@@ -111,13 +128,23 @@ impl EsiClient {
 
         match self.validate_jwt(&access_token).await {
             Ok(claims) => {
-                let user = models::User::new(access_token, refresh_token, claims);
+                let mut user = models::User::new(access_token, refresh_token, claims);
                 tracing::trace!(
                     user.access_token,
                     user.refresh_token,
                     expires_at = format!("{}", user.expires_at),
                     "validated token"
                 );
+
+                match self.get_character_info(&user).await {
+                    Ok(corporation_id) => user.corporation_id = Some(corporation_id),
+                    Err(e) => tracing::error!(
+                        character_id = user.character_id,
+                        error = e.to_string(),
+                        "failed to resolve character's corporation"
+                    ),
+                }
+
                 Ok(user)
             }
             Err(e) => Err(anyhow::format_err!("failed to validate JWT: {e}")),
@@ -125,8 +152,11 @@ impl EsiClient {
     }
 
     fn build_basic_auth(&self) -> String {
-        base64::engine::general_purpose::URL_SAFE
-            .encode(format!("{}:{}", self.app_id, self.app_secret))
+        base64::engine::general_purpose::URL_SAFE.encode(format!(
+            "{}:{}",
+            self.config.application_id(),
+            self.config.application_secret()
+        ))
     }
 
     fn build_payload(&self, token: Token) -> HashMap<String, String> {
@@ -147,7 +177,7 @@ impl EsiClient {
         let decoding_key = Self::get_rsa256_key().await.unwrap();
         let mut validations = Validation::new(Algorithm::RS256);
         validations.required_spec_claims = vec![String::from("sub")].into_iter().collect();
-        let aud = vec![self.app_id.clone(), "EVE Online".to_string()];
+        let aud = vec![self.config.application_id(), "EVE Online".to_string()];
         validations.set_audience(&aud);
 
         let token = token.trim_matches('"').to_string();
@@ -190,7 +220,10 @@ impl EsiClient {
         }
     }
 
-    async fn get_personal_killmails(&self, user: &models::User) -> Result<(), anyhow::Error> {
+    async fn get_personal_killmails(
+        &self,
+        user: &models::User,
+    ) -> Result<Vec<KillmailItem>, anyhow::Error> {
         tracing::debug!(
             id = user.character_id,
             last_fetched = user
@@ -228,69 +261,131 @@ impl EsiClient {
                 text
             ));
         }
-        let killmails: Vec<KillmailItem> = serde_json::from_str(&text)?;
 
-        for km in killmails {
-            let killmail = models::Killmail {
-                killmail_id: km.killmail_id,
-                killmail_hash: km.killmail_hash,
-                status: "new".to_string(),
-            };
+        Ok(serde_json::from_str(&text)?)
+    }
 
-            if let Err(err) = self
-                .jobs_sender
-                .send(processor::Job::SaveKillmail(killmail))
-                .await
-            {
-                tracing::error!(
-                    character_id = user.character_id,
-                    error = err.to_string(),
-                    "failed to enqueue save job"
-                );
+    /// Pages through `corporations/{corporation_id}/killmails/recent/`
+    /// following ESI's `X-Pages` header, since that endpoint (unlike the
+    /// per-character one) is paginated. Returns an empty list if the user's
+    /// corporation hasn't been resolved yet.
+    pub async fn get_corporation_killmails(
+        &self,
+        user: &models::User,
+    ) -> Result<Vec<KillmailItem>, anyhow::Error> {
+        let Some(corporation_id) = user.corporation_id else {
+            return Ok(Vec::new());
+        };
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            "Authorization",
+            format!("Bearer {}", user.access_token).as_str().parse()?,
+        );
+
+        let client = reqwest::Client::new();
+        let mut killmails = Vec::new();
+        let mut page: u32 = 1;
+        loop {
+            let response = client
+                .get(format!(
+                    "https://esi.evetech.net/latest/corporations/{corporation_id}/killmails/recent/?page={page}",
+                ))
+                .headers(headers.clone())
+                .send()
+                .await?;
+
+            let status = response.status();
+            let total_pages = response
+                .headers()
+                .get("X-Pages")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(1);
+            let text = response.text().await?;
+            if !status.is_success() {
+                return Err(anyhow::format_err!(
+                    "request failed with status {}: {}",
+                    status,
+                    text
+                ));
             }
+
+            let page_items: Vec<KillmailItem> = serde_json::from_str(&text)?;
+            killmails.extend(page_items);
+
+            if page >= total_pages {
+                break;
+            }
+            page += 1;
         }
 
-        Ok(())
+        Ok(killmails)
     }
 
-    pub async fn _get_character_info(&self, user: &models::User) -> Result<(), anyhow::Error> {
-        let response = match reqwest::Client::new()
+    pub async fn get_character_info(&self, user: &models::User) -> Result<i64, anyhow::Error> {
+        let response = reqwest::Client::new()
             .get(format!(
-                "https://esi.evetech.net/characters/{}",
+                "https://esi.evetech.net/latest/characters/{}/",
                 user.character_id
             ))
             .send()
             .await
-        {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(anyhow::format_err!("failed to send request: {e}"));
+            .map_err(|e| anyhow::format_err!("failed to send request: {e}"))?;
+
+        let result = Self::json(response).await?;
+        result["corporation_id"]
+            .as_i64()
+            .ok_or_else(|| anyhow::format_err!("character info missing corporation_id"))
+    }
+
+    /// Resolves `ids` to names via `/universe/names/` (up to 1000 per
+    /// request), consulting `entity_cache` first so ids already looked up
+    /// this process don't get refetched.
+    pub async fn resolve_entities(&self, ids: Vec<i64>) -> Result<Vec<models::Entity>, anyhow::Error> {
+        let mut resolved = Vec::new();
+        let mut to_fetch = Vec::new();
+        for id in ids {
+            match self.entity_cache.get(&id) {
+                Some(entity) => resolved.push(entity.clone()),
+                None => to_fetch.push(id),
             }
-        };
+        }
 
-        let result = match Self::json(response).await {
-            Ok(json) => json,
-            Err(e) => {
-                return Err(anyhow::format_err!("failed to decode JSON: {e}"));
+        let client = reqwest::Client::new();
+        for chunk in to_fetch.chunks(1000) {
+            let response = client
+                .post("https://esi.evetech.net/latest/universe/names/")
+                .json(chunk)
+                .send()
+                .await
+                .map_err(|e| anyhow::format_err!("failed to send request: {e}"))?;
+
+            let status = response.status();
+            let text = response.text().await?;
+            if !status.is_success() {
+                return Err(anyhow::format_err!(
+                    "request failed with status {}: {}",
+                    status,
+                    text
+                ));
             }
-        };
 
-        let corp_id = result["corporation_id"].as_i64().unwrap_or(0);
-        if corp_id != 0 {
-            // self._get_corp_info(corp_id).await;
+            let items: Vec<NameItem> = serde_json::from_str(&text)?;
+            for item in items {
+                let entity = models::Entity {
+                    id: item.id,
+                    name: item.name,
+                    type_: item.category,
+                };
+                self.entity_cache.insert(entity.id, entity.clone());
+                resolved.push(entity);
+            }
         }
 
-        // Ok(CharacterData {
-        //     name: result["name"].to_string().trim_matches('"').to_string(),
-        //     corp_id: result["corporation_id"].as_i64().unwrap_or(0),
-        //     alliance_id: None,
-        //     updated_at: Some(chrono::Utc::now()),
-        // })
-        Ok(())
+        Ok(resolved)
     }
 
-    pub async fn _get_corp_info(&self, _corp_id: i64) {}
-
     async fn json(response: Response) -> Result<Value, anyhow::Error> {
         let status = response.status();
         let text = response.text().await?;
@@ -319,7 +414,7 @@ impl EsiClient {
                     );
 
                     if let Err(err) = self
-                        .jobs_sender
+                        .queue
                         .send(processor::Job::SaveCharacter(new_user))
                         .await
                     {
@@ -346,18 +441,66 @@ impl EsiClient {
         let mut set = tokio::task::JoinSet::new();
         for user in users {
             let self_clone = self.clone();
-            set.spawn(async move {
-                if let Err(e) = self_clone.get_personal_killmails(&user).await {
-                    tracing::error!(
-                        error = e.to_string(),
-                        "Failed fetch killmails for character"
-                    );
-                }
-            });
+            set.spawn(async move { self_clone.fetch_and_enqueue_killmails(&user).await });
         }
         set.join_all().await;
     }
 
+    /// Fetches both the character's personal killmails and, if their
+    /// corporation has been resolved, the corporation's killmails, merges
+    /// the two lists (de-duplicating by `killmail_id`, since a kill a member
+    /// was involved in shows up in both feeds), and enqueues a
+    /// `SaveKillmail` job for each.
+    async fn fetch_and_enqueue_killmails(&self, user: &models::User) {
+        let mut seen = std::collections::HashSet::new();
+        let mut killmails = Vec::new();
+
+        match self.get_personal_killmails(user).await {
+            Ok(items) => killmails.extend(items),
+            Err(e) => tracing::error!(
+                character_id = user.character_id,
+                error = e.to_string(),
+                "failed to fetch personal killmails"
+            ),
+        }
+
+        match self.get_corporation_killmails(user).await {
+            Ok(items) => killmails.extend(items),
+            Err(e) => tracing::error!(
+                character_id = user.character_id,
+                error = e.to_string(),
+                "failed to fetch corporation killmails"
+            ),
+        }
+
+        for km in killmails {
+            if !seen.insert(km.killmail_id) {
+                continue;
+            }
+
+            let killmail = models::Killmail {
+                killmail_id: km.killmail_id,
+                killmail_hash: km.killmail_hash,
+                status: "new".to_string(),
+                attempts: 0,
+                next_retry_at: chrono::Utc::now(),
+                notified_character_id: None,
+            };
+
+            if let Err(err) = self
+                .queue
+                .send(processor::Job::SaveKillmail(user.character_id, killmail))
+                .await
+            {
+                tracing::error!(
+                    character_id = user.character_id,
+                    error = err.to_string(),
+                    "failed to enqueue save job"
+                );
+            }
+        }
+    }
+
     pub async fn resolve_killmails(&self, killmails: Vec<Killmail>) {
         tracing::debug!(len = killmails.len(), "resolving killmails");
         let mut set = tokio::task::JoinSet::new();
@@ -407,7 +550,12 @@ impl EsiClient {
             }
         };
 
-        let mut entities: Vec<models::Entity> = Vec::new();
+        // `entity_side` records how each entity relates to the killmail
+        // (e.g. "victim_character", "attacker_ship_type"), so it can be
+        // written alongside the entity itself into `killmails_x_entities` -
+        // what entity-scoped WebSocket filtering and email notifications
+        // key off of.
+        let mut entities: Vec<(models::Entity, &'static str)> = Vec::new();
 
         let solar_system: models::Entity;
         if let Some(system_id) = result.get("solar_system_id").and_then(|id| id.as_i64()) {
@@ -424,75 +572,102 @@ impl EsiClient {
                 type_: "solar_system".to_string(),
             };
         }
-        entities.push(solar_system);
+        entities.push((solar_system, "solar_system"));
         if let Some(victim) = result.get("victim") {
             if let Some(char_id) = victim.get("character_id").and_then(|id| id.as_i64()) {
-                entities.push(models::Entity {
-                    id: char_id,
-                    name: "".to_string(),
-                    type_: "character".to_string(),
-                });
+                entities.push((
+                    models::Entity {
+                        id: char_id,
+                        name: "".to_string(),
+                        type_: "character".to_string(),
+                    },
+                    "victim_character",
+                ));
             }
             if let Some(corp_id) = victim.get("corporation_id").and_then(|id| id.as_i64()) {
-                entities.push(models::Entity {
-                    id: corp_id,
-                    name: "".to_string(),
-                    type_: "corporation".to_string(),
-                });
+                entities.push((
+                    models::Entity {
+                        id: corp_id,
+                        name: "".to_string(),
+                        type_: "corporation".to_string(),
+                    },
+                    "victim_corporation",
+                ));
             }
             if let Some(alliance_id) = victim.get("alliance_id").and_then(|id| id.as_i64()) {
-                entities.push(models::Entity {
-                    id: alliance_id,
-                    name: "".to_string(),
-                    type_: "alliance".to_string(),
-                });
+                entities.push((
+                    models::Entity {
+                        id: alliance_id,
+                        name: "".to_string(),
+                        type_: "alliance".to_string(),
+                    },
+                    "victim_alliance",
+                ));
             }
             if let Some(weapon_type_id) = victim.get("weapon_type_id").and_then(|id| id.as_i64()) {
-                entities.push(models::Entity {
-                    id: weapon_type_id,
-                    name: "".to_string(),
-                    type_: "weapon_type".to_string(),
-                });
+                entities.push((
+                    models::Entity {
+                        id: weapon_type_id,
+                        name: "".to_string(),
+                        type_: "weapon_type".to_string(),
+                    },
+                    "victim_weapon_type",
+                ));
             }
             if let Some(ship_type_id) = victim.get("ship_type_id").and_then(|id| id.as_i64()) {
-                entities.push(models::Entity {
-                    id: ship_type_id,
-                    name: "".to_string(),
-                    type_: "ship_type".to_string(),
-                });
+                entities.push((
+                    models::Entity {
+                        id: ship_type_id,
+                        name: "".to_string(),
+                        type_: "ship_type".to_string(),
+                    },
+                    "victim_ship_type",
+                ));
             }
         }
 
         if let Some(attackers) = result.get("attackers").and_then(|a| a.as_array()) {
             for attacker in attackers {
                 if let Some(char_id) = attacker.get("character_id").and_then(|id| id.as_i64()) {
-                    entities.push(models::Entity {
-                        id: char_id,
-                        name: "".to_string(),
-                        type_: "character".to_string(),
-                    });
+                    entities.push((
+                        models::Entity {
+                            id: char_id,
+                            name: "".to_string(),
+                            type_: "character".to_string(),
+                        },
+                        "attacker_character",
+                    ));
                 }
                 if let Some(corp_id) = attacker.get("corporation_id").and_then(|id| id.as_i64()) {
-                    entities.push(models::Entity {
-                        id: corp_id,
-                        name: "".to_string(),
-                        type_: "corporation".to_string(),
-                    });
+                    entities.push((
+                        models::Entity {
+                            id: corp_id,
+                            name: "".to_string(),
+                            type_: "corporation".to_string(),
+                        },
+                        "attacker_corporation",
+                    ));
                 }
                 if let Some(alliance_id) = attacker.get("alliance_id").and_then(|id| id.as_i64()) {
-                    entities.push(models::Entity {
-                        id: alliance_id,
-                        name: "".to_string(),
-                        type_: "alliance".to_string(),
-                    });
+                    entities.push((
+                        models::Entity {
+                            id: alliance_id,
+                            name: "".to_string(),
+                            type_: "alliance".to_string(),
+                        },
+                        "attacker_alliance",
+                    ));
                 }
                 if let Some(ship_type_id) = attacker.get("ship_type_id").and_then(|id| id.as_i64())
                 {
-                    entities.push(models::Entity {
-                        id: ship_type_id,
-                        name: "".to_string(),
-                        type_: "ship_type".to_string(),
-                    });
+                    entities.push((
+                        models::Entity {
+                            id: ship_type_id,
+                            name: "".to_string(),
+                            type_: "ship_type".to_string(),
+                        },
+                        "attacker_ship_type",
+                    ));
                 }
             }
         }
@@ -502,20 +677,35 @@ impl EsiClient {
             len = entities.len(),
             "entities collected from killmail"
         );
-        for entity in entities {
-            tracing::trace!(entity = format!("{entity:?}"), "debugging entity");
+        for (entity, entity_side) in entities {
+            tracing::trace!(entity = format!("{entity:?}"), entity_side, "debugging entity");
             if entity.id == 0 {
                 continue;
             }
+            let entity_id = entity.id;
+            let entity_type = entity.type_.clone();
+            if let Err(err) = self.queue.send(processor::Job::SaveEntity(entity)).await {
+                tracing::error!(
+                    killmail_id,
+                    error = err.to_string(),
+                    "failed to enqueue save job"
+                );
+            }
             if let Err(err) = self
-                .jobs_sender
-                .send(processor::Job::SaveEntity(entity))
+                .queue
+                .send(processor::Job::SaveKillmailEntity(
+                    killmail_id,
+                    entity_id,
+                    entity_type,
+                    entity_side.to_string(),
+                ))
                 .await
             {
                 tracing::error!(
                     killmail_id,
+                    entity_id,
                     error = err.to_string(),
-                    "failed to enqueue save job"
+                    "failed to enqueue killmail-entity link job"
                 );
             }
         }
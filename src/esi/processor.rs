@@ -1,112 +1,429 @@
+use std::sync::Arc;
+
+use tokio_util::sync::CancellationToken;
+
 use diesel::dsl::{IntervalDsl, now};
-use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::sql_types::Timestamptz;
-use diesel::{ExpressionMethods, IntoSql, QueryDsl, RunQueryDsl};
+use diesel::{ExpressionMethods, IntoSql, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
 
 use crate::esi::EsiClient;
-use crate::storage::handlers::{save_killmail, save_user};
+use crate::notifier::{EmailNotifier, KillmailEvent};
+use crate::queue::JobQueue;
+use crate::storage::handlers::{
+    DbPool, claim_killmails_for_resolution, get_killmail, killmail_entity_ids,
+    mark_killmail_failed, mark_killmail_notified, mark_killmail_resolved, resolve_entity,
+    save_entity, save_error, save_killmail, save_killmail_entity, save_user,
+    unresolved_entity_ids, users_to_notify,
+};
 use crate::storage::schema::users::expires_at;
 use crate::storage::{models, schema};
 
+/// A killmail that fails to resolve this many times parks at `failed`
+/// without further retries.
+pub const MAX_ATTEMPTS: i32 = 10;
+/// Base exponential backoff delay; doubled per attempt up to `BACKOFF_CAP`.
+pub const BACKOFF_BASE: chrono::Duration = chrono::Duration::seconds(30);
+pub const BACKOFF_CAP: chrono::Duration = chrono::Duration::hours(1);
+/// How many killmails a single `ResolveKillmails` tick claims at once.
+const RESOLVE_BATCH_SIZE: i64 = 100;
+/// ESI's `/universe/names/` endpoint accepts at most 1000 ids per call, so
+/// a single `ResolveEntities` tick claims at most that many unresolved rows.
+const ENTITY_BATCH_SIZE: i64 = 1000;
+
+/// A job that fails this many times moves to `dead` for inspection instead
+/// of retrying forever.
+const MAX_JOB_ATTEMPTS: i32 = 10;
+const JOB_BACKOFF_BASE: chrono::Duration = chrono::Duration::seconds(10);
+const JOB_BACKOFF_CAP: chrono::Duration = chrono::Duration::minutes(5);
+/// How many jobs a single worker tick claims at once.
+const JOB_BATCH_SIZE: i64 = 20;
+/// Upper bound on how long a worker waits between polls when it hasn't
+/// heard a `NOTIFY` - a safety net in case a notification is ever missed.
+const POLL_FALLBACK: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Job {
     Refresh,
     Killmails,
+    ResolveKillmails,
     Killmail(i64, String),
-    Character(i64),
-    Corporation(i64),
-    Alliance(i64),
+    ResolveEntities,
     SaveCharacter(models::User),
-    SaveKillmail(models::Killmail),
-    Stop,
+    SaveKillmail(i64, models::Killmail),
+    SaveEntity(models::Entity),
+    /// Records that `entity_id` (of kind `entity_type`) appeared on
+    /// `killmail_id` as `entity_side` (e.g. `"victim_character"`), so
+    /// WebSocket filtering and email notifications can find the killmail by
+    /// entity later. Carries `entity_type` so it can upsert a placeholder
+    /// `entities` row itself rather than depending on the independently
+    /// enqueued `Job::SaveEntity` for the same entity to land first.
+    SaveKillmailEntity(i64, i64, String, String),
+    /// Sent right after a killmail is freshly persisted, so a character's
+    /// owner hears about it well before it's resolved.
+    Notify(i64, i64),
+}
+
+impl Job {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::Refresh => "refresh",
+            Job::Killmails => "killmails",
+            Job::ResolveKillmails => "resolve_killmails",
+            Job::Killmail(..) => "killmail",
+            Job::ResolveEntities => "resolve_entities",
+            Job::SaveCharacter(..) => "save_character",
+            Job::SaveKillmail(..) => "save_killmail",
+            Job::SaveEntity(..) => "save_entity",
+            Job::SaveKillmailEntity(..) => "save_killmail_entity",
+            Job::Notify(..) => "notify",
+        }
+    }
 }
 
 pub struct Processor {
-    pub pool: Pool<ConnectionManager<PgConnection>>,
+    pub pool: DbPool,
     pub client: EsiClient,
+    pub queue: JobQueue,
+    pub killmail_events: tokio::sync::broadcast::Sender<KillmailEvent>,
+    pub email: Option<Arc<EmailNotifier>>,
 }
 
 impl Processor {
-    pub fn new(pool: Pool<ConnectionManager<PgConnection>>, client: &EsiClient) -> Self {
+    pub fn new(
+        pool: DbPool,
+        client: &EsiClient,
+        queue: JobQueue,
+        killmail_events: tokio::sync::broadcast::Sender<KillmailEvent>,
+    ) -> Self {
+        let email = EmailNotifier::from_config(&client.config().snapshot()).map(Arc::new);
         Processor {
             pool,
             client: client.clone(),
+            queue,
+            killmail_events,
+            email,
         }
     }
 
-    pub async fn start(&self, mut jobs_rx: tokio::sync::mpsc::Receiver<Job>) {
+    /// Claims ready jobs and runs them until `stop` is cancelled, waking
+    /// either on a `NOTIFY mkb_jobs` (via `notify`) or the fallback poll
+    /// timer. Returns the task handle so shutdown can await in-flight work
+    /// draining before exiting.
+    pub async fn start(
+        &self,
+        mut notify: tokio::sync::mpsc::Receiver<()>,
+        stop: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
         let pool = self.pool.clone();
         let client = self.client.clone();
+        let queue = self.queue.clone();
+        let killmail_events = self.killmail_events.clone();
+        let email = self.email.clone();
         tokio::spawn(async move {
-            while let Some(job) = jobs_rx.recv().await {
-                match job {
-                    Job::Refresh => {
-                        let mut conn = pool.get().unwrap();
-                        let users = match schema::users::dsl::users
-                            .filter(expires_at.gt(now.into_sql::<Timestamptz>() - 20.minutes()))
-                            .load::<models::User>(&mut conn)
-                        {
-                            Ok(users) => users,
-                            Err(e) => {
-                                tracing::error!(error = e.to_string(), "Failed to refresh users");
-                                continue;
-                            }
-                        };
+            loop {
+                if stop.is_cancelled() {
+                    break;
+                }
 
-                        client.refresh(users).await;
+                let claimed = match queue.claim(JOB_BATCH_SIZE).await {
+                    Ok(claimed) => claimed,
+                    Err(e) => {
+                        tracing::error!(error = e.to_string(), "failed to claim jobs");
+                        Vec::new()
                     }
-                    Job::Killmails => {
-                        let mut conn = pool.get().unwrap();
-                        let users = match schema::users::dsl::users.load::<models::User>(&mut conn)
-                        {
-                            Ok(users) => users,
-                            Err(e) => {
-                                tracing::error!(error = e.to_string(), "Failed to refresh users");
-                                continue;
-                            }
-                        };
+                };
 
-                        client.get_killmails(users).await;
-                    }
-                    Job::Killmail(killmail_id, killmail_hash) => {
-                        tracing::info!(killmail_id, killmail_hash, "processing killmail");
-                        // Here you would typically fetch the killmail data from ESI and store it in the database
-                    }
-                    Job::Character(character_id) => {
-                        tracing::info!(character_id, "resolving character ID");
-                        // Fetch and store character data
+                if claimed.is_empty() {
+                    tokio::select! {
+                        _ = notify.recv() => {}
+                        _ = tokio::time::sleep(POLL_FALLBACK) => {}
+                        _ = stop.cancelled() => {
+                            break;
+                        }
                     }
-                    Job::Corporation(corporation_id) => {
-                        tracing::info!(corporation_id, "resolving corporation ID");
-                        // Fetch and store corporation data
+                    continue;
+                }
+
+                for (job_id, job) in claimed {
+                    let outcome = Self::run(
+                        &pool,
+                        &client,
+                        &queue,
+                        &killmail_events,
+                        &email,
+                        job,
+                    )
+                    .await;
+
+                    let result = match outcome {
+                        Ok(()) => queue.complete(job_id).await,
+                        Err(e) => {
+                            tracing::error!(
+                                id = %job_id,
+                                error = e.to_string(),
+                                "job failed"
+                            );
+                            queue
+                                .fail(job_id, JOB_BACKOFF_BASE, JOB_BACKOFF_CAP, MAX_JOB_ATTEMPTS)
+                                .await
+                        }
+                    };
+
+                    if let Err(e) = result {
+                        tracing::error!(
+                            id = %job_id,
+                            error = e.to_string(),
+                            "failed to update job status"
+                        );
                     }
-                    Job::Alliance(alliance_id) => {
-                        tracing::info!(alliance_id, "resolving alliance ID");
-                        // Fetch and store alliance data
+                }
+            }
+
+            tracing::info!("processor received stop signal, shutting down");
+        })
+    }
+
+    async fn run(
+        pool: &DbPool,
+        client: &EsiClient,
+        queue: &JobQueue,
+        killmail_events: &tokio::sync::broadcast::Sender<KillmailEvent>,
+        email: &Option<Arc<EmailNotifier>>,
+        job: Job,
+    ) -> Result<(), anyhow::Error> {
+        match job {
+            Job::Refresh => {
+                let mut conn = pool
+                    .get()
+                    .await
+                    .map_err(|e| anyhow::format_err!("failed to check out connection: {e}"))?;
+                let users = schema::users::dsl::users
+                    .filter(expires_at.gt(now.into_sql::<Timestamptz>() - 20.minutes()))
+                    .load::<models::User>(&mut conn)
+                    .await
+                    .map_err(|e| anyhow::format_err!("query failed: {e}"))?;
+
+                client.refresh(users).await;
+                Ok(())
+            }
+            Job::Killmails => {
+                let mut conn = pool
+                    .get()
+                    .await
+                    .map_err(|e| anyhow::format_err!("failed to check out connection: {e}"))?;
+                let users = schema::users::dsl::users
+                    .load::<models::User>(&mut conn)
+                    .await
+                    .map_err(|e| anyhow::format_err!("query failed: {e}"))?;
+
+                client.get_killmails(users).await;
+                Ok(())
+            }
+            Job::ResolveKillmails => {
+                let claimed =
+                    claim_killmails_for_resolution(pool, MAX_ATTEMPTS, RESOLVE_BATCH_SIZE).await?;
+
+                tracing::debug!(len = claimed.len(), "claimed killmails for resolution");
+                for killmail in claimed {
+                    if let Err(err) = queue
+                        .send(Job::Killmail(killmail.killmail_id, killmail.killmail_hash))
+                        .await
+                    {
+                        tracing::error!(
+                            killmail_id = killmail.killmail_id,
+                            error = err.to_string(),
+                            "failed to enqueue killmail resolution job"
+                        );
                     }
-                    Job::SaveCharacter(user) => {
-                        tracing::debug!(character_id = user.character_id, "saving user");
-                        // Save or update the user in the database
-                        if let Err(e) = save_user(&pool, user) {
-                            tracing::error!(error = e.to_string(), "Failed to save user");
+                }
+                Ok(())
+            }
+            Job::Killmail(killmail_id, killmail_hash) => {
+                tracing::info!(killmail_id, killmail_hash, "resolving killmail");
+                match client.get_killmail_data(killmail_id, killmail_hash.clone()).await {
+                    Ok(()) => {
+                        if let Err(e) = mark_killmail_resolved(pool, killmail_id).await {
+                            tracing::error!(
+                                killmail_id,
+                                error = e.to_string(),
+                                "Failed to mark killmail resolved"
+                            );
+                        }
+
+                        let entity_ids =
+                            killmail_entity_ids(pool, killmail_id).await.unwrap_or_default();
+                        let event = KillmailEvent {
+                            killmail_id,
+                            killmail_hash: killmail_hash.clone(),
+                            entity_ids: entity_ids.clone(),
                         };
+                        // Only errors if there are no subscribers; nothing to act on.
+                        let _ = killmail_events.send(event.clone());
+
+                        if let Some(email) = &email {
+                            // A participant who already got the immediate
+                            // "new killmail" email (`Job::Notify`, right
+                            // after save) shouldn't also get this "resolved"
+                            // one - their own `character_id` is necessarily
+                            // among `entity_ids`.
+                            let already_notified = get_killmail(pool, killmail_id)
+                                .await
+                                .ok()
+                                .flatten()
+                                .and_then(|km| km.notified_character_id);
+
+                            match users_to_notify(pool, entity_ids).await {
+                                Ok(users) => {
+                                    for user in users {
+                                        if Some(user.character_id) == already_notified {
+                                            continue;
+                                        }
+                                        if let Some(to) = &user.notify_email {
+                                            if let Err(e) = email.notify(to, &event).await {
+                                                tracing::error!(
+                                                    killmail_id,
+                                                    error = e.to_string(),
+                                                    "Failed to send notification email"
+                                                );
+                                            }
+                                        }
+                                    }
+                                }
+                                Err(e) => tracing::error!(
+                                    killmail_id,
+                                    error = e.to_string(),
+                                    "Failed to look up users to notify"
+                                ),
+                            }
+                        }
+                        Ok(())
                     }
-                    Job::SaveKillmail(killmail) => {
-                        tracing::debug!(
-                            killmail_id = killmail.killmail_id,
-                            killmail_hash = killmail.killmail_hash,
-                            "saving killmail"
+                    Err(e) => {
+                        tracing::error!(
+                            killmail_id,
+                            error = e.to_string(),
+                            "Failed to resolve killmail"
                         );
-                        if let Err(e) = save_killmail(&pool, killmail) {
-                            tracing::error!(error = e.to_string(), "Failed to save killmail");
+                        if let Err(e) =
+                            save_error(pool, "resolve_killmail".to_string(), e.to_string()).await
+                        {
+                            tracing::error!(killmail_id, error = e.to_string(), "Failed to save error");
+                        }
+                        if let Err(e) =
+                            mark_killmail_failed(pool, killmail_id, BACKOFF_BASE, BACKOFF_CAP).await
+                        {
+                            tracing::error!(
+                                killmail_id,
+                                error = e.to_string(),
+                                "Failed to mark killmail failed"
+                            );
                         }
+                        // `killmails.attempts`/`next_retry_at` (just updated above)
+                        // already owns this killmail's retry/backoff schedule end
+                        // to end, via `ResolveKillmails` re-claiming it - so this
+                        // returns `Ok` rather than `Err`, the same way
+                        // `Job::ResolveEntities` swallows per-item errors, to avoid
+                        // *also* retrying on the generic job queue's own, slower
+                        // schedule and resolving the same killmail twice.
+                        Ok(())
                     }
-                    Job::Stop => {
-                        tracing::info!("Stopping processor.");
-                        break;
+                }
+            }
+            Job::ResolveEntities => {
+                let ids = unresolved_entity_ids(pool, ENTITY_BATCH_SIZE).await?;
+                if ids.is_empty() {
+                    return Ok(());
+                }
+
+                tracing::debug!(len = ids.len(), "resolving entity names");
+                let entities = client.resolve_entities(ids).await?;
+                for entity in entities {
+                    if let Err(e) =
+                        resolve_entity(pool, entity.id, entity.name, entity.type_).await
+                    {
+                        tracing::error!(
+                            entity_id = entity.id,
+                            error = e.to_string(),
+                            "failed to save resolved entity"
+                        );
+                    }
+                }
+                Ok(())
+            }
+            Job::SaveCharacter(user) => {
+                tracing::debug!(character_id = user.character_id, "saving user");
+                save_user(pool, user).await?;
+                Ok(())
+            }
+            Job::SaveKillmail(character_id, killmail) => {
+                tracing::debug!(
+                    killmail_id = killmail.killmail_id,
+                    killmail_hash = killmail.killmail_hash,
+                    "saving killmail"
+                );
+                let killmail_id = killmail.killmail_id;
+                let inserted = save_killmail(pool, killmail).await?;
+                if inserted > 0 {
+                    if let Err(err) = queue.send(Job::Notify(character_id, killmail_id)).await {
+                        tracing::error!(
+                            killmail_id,
+                            error = err.to_string(),
+                            "failed to enqueue new killmail notification job"
+                        );
+                    }
+                }
+                Ok(())
+            }
+            Job::SaveEntity(entity) => {
+                tracing::debug!(entity_id = entity.id, "saving entity");
+                save_entity(pool, entity).await?;
+                Ok(())
+            }
+            Job::SaveKillmailEntity(killmail_id, entity_id, entity_type, entity_side) => {
+                tracing::debug!(killmail_id, entity_id, entity_side, "saving killmail entity");
+                save_killmail_entity(pool, killmail_id, entity_id, entity_type, entity_side)
+                    .await?;
+                Ok(())
+            }
+            Job::Notify(character_id, killmail_id) => {
+                let Some(email) = email else {
+                    return Ok(());
+                };
+
+                match users_to_notify(pool, vec![character_id]).await {
+                    Ok(users) => {
+                        for user in users {
+                            if let Some(to) = &user.notify_email {
+                                if let Err(e) = email.notify_new_killmail(to, killmail_id).await {
+                                    tracing::error!(
+                                        character_id,
+                                        killmail_id,
+                                        error = e.to_string(),
+                                        "Failed to send new killmail notification email"
+                                    );
+                                } else if let Err(e) =
+                                    mark_killmail_notified(pool, killmail_id, character_id).await
+                                {
+                                    tracing::error!(
+                                        character_id,
+                                        killmail_id,
+                                        error = e.to_string(),
+                                        "Failed to record killmail notification"
+                                    );
+                                }
+                            }
+                        }
                     }
+                    Err(e) => tracing::error!(
+                        character_id,
+                        error = e.to_string(),
+                        "Failed to look up user to notify"
+                    ),
                 }
+                Ok(())
             }
-        });
+        }
     }
 }
@@ -1,17 +1,18 @@
 use std::time::Duration;
 
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 use crate::esi::processor::Job;
+use crate::queue::JobQueue;
 
 pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
 pub const FETCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
 pub const RESOLVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-pub async fn start_scheduler(
-    mut stop: tokio::sync::oneshot::Receiver<()>,
-    scheduler_sender: tokio::sync::mpsc::Sender<Job>,
-) -> JoinHandle<()> {
+/// Runs the interval ticks until `stop` is cancelled, returning the task
+/// handle so shutdown can await it draining before exiting.
+pub async fn start_scheduler(stop: CancellationToken, queue: JobQueue) -> JoinHandle<()> {
     tokio::spawn(async move {
         let mut refresh_interval = tokio::time::interval(REFRESH_INTERVAL);
         let mut fetch_interval = tokio::time::interval(FETCH_INTERVAL);
@@ -21,17 +22,18 @@ pub async fn start_scheduler(
             tokio::select! {
                 _ = refresh_interval.tick() => {
                     tracing::info!("refresh?");
-                    let _ = scheduler_sender.send(Job::Refresh).await;
+                    let _ = queue.send(Job::Refresh).await;
                 }
                 _ = fetch_interval.tick() => {
                     tracing::info!("fetch?");
-                    let _ = scheduler_sender.send(Job::Killmails).await;
+                    let _ = queue.send(Job::Killmails).await;
                 }
                 _ = resolve_interval.tick() => {
                     tracing::info!("resolve?");
-                    // add refresh here
+                    let _ = queue.send(Job::ResolveKillmails).await;
+                    let _ = queue.send(Job::ResolveEntities).await;
                 }
-                _ = &mut stop => {
+                _ = stop.cancelled() => {
                     tracing::info!("Scheduler received stop signal, shutting down.");
                     break;
                 }
@@ -0,0 +1,29 @@
+use axum::extract::Request;
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use reqwest::StatusCode;
+
+use crate::http::state::AppState;
+
+/// Gates the management API (`/users*`, `/killmails*`) behind a shared
+/// secret, since those routes read/delete tracked characters and killmails
+/// and have no business being open to the internet. Expects
+/// `Authorization: Bearer <MKB_ADMIN_TOKEN>`.
+pub async fn require_admin_token(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let provided = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if provided != Some(state.admin_token.as_str()) {
+        return (StatusCode::UNAUTHORIZED, "").into_response();
+    }
+
+    next.run(req).await
+}
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 
 use axum::{
-    extract::{Query, State},
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{Path, Query, State},
     response::{Html, IntoResponse},
 };
 use axum_extra::extract::{CookieJar, cookie::Cookie};
@@ -9,11 +10,53 @@ use reqwest::StatusCode;
 
 use crate::esi;
 use crate::http::state::AppState;
+use crate::storage;
 
 pub async fn index() -> impl IntoResponse {
     (StatusCode::OK, "Hello, World!")
 }
 
+/// Streams `KillmailEvent`s as JSON as soon as they're broadcast by the
+/// processor. `?entity_id=<id>` restricts the stream to killmails touching
+/// that entity (e.g. a tracked `corporation_id` or `ship_type_id`).
+pub async fn ws_killmails(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let entity_filter: Option<i64> = params.get("entity_id").and_then(|v| v.parse().ok());
+    ws.on_upgrade(move |socket| handle_killmail_socket(socket, state, entity_filter))
+}
+
+async fn handle_killmail_socket(mut socket: WebSocket, state: AppState, entity_filter: Option<i64>) {
+    let mut events = state.killmail_events.subscribe();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+
+        if let Some(entity_id) = entity_filter {
+            if !event.matches(entity_id) {
+                continue;
+            }
+        }
+
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!(error = e.to_string(), "failed to serialize killmail event");
+                continue;
+            }
+        };
+
+        if socket.send(Message::Text(payload.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
 pub async fn auth(State(state): State<AppState>, jar: CookieJar) -> impl IntoResponse {
     let (url, nonce) = state.client.build_auth_url();
 
@@ -52,23 +95,17 @@ pub async fn callback(
 
     let code = params.get("code").unwrap_or(&String::new()).to_owned();
 
-    let _ = state
+    match state
         .client
         .clone()
         .token_exchange(esi::Token::AuthCode(code))
         .await
-        .map_err(|e| {
-            tracing::error!(error = e.to_string(), "Failed to exchange token");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                [("Content-Type", "text/html")],
-                e.to_string(),
-            )
-        })
-        .map(|user| {
+    {
+        Ok(user) => {
             if state
-                .jobs_sender
-                .try_send(esi::processor::Job::SaveCharacter(user.clone()))
+                .queue
+                .send(esi::processor::Job::SaveCharacter(user.clone()))
+                .await
                 .is_err()
             {
                 tracing::error!(
@@ -76,7 +113,11 @@ pub async fn callback(
                     "Failed to enqueue user save job"
                 );
             }
-        });
+        }
+        Err(e) => {
+            tracing::error!(error = e.to_string(), "Failed to exchange token");
+        }
+    }
 
     (
         StatusCode::OK,
@@ -87,8 +128,9 @@ pub async fn callback(
 
 pub async fn refresh(State(state): State<AppState>) -> impl IntoResponse {
     state
-        .jobs_sender
-        .try_send(esi::processor::Job::Refresh)
+        .queue
+        .send(esi::processor::Job::Refresh)
+        .await
         .map_err(|e| {
             tracing::error!(error = e.to_string(), "Failed to enqueue refresh job");
             (
@@ -108,8 +150,9 @@ pub async fn refresh(State(state): State<AppState>) -> impl IntoResponse {
 
 pub async fn killmails(State(state): State<AppState>) -> impl IntoResponse {
     state
-        .jobs_sender
-        .try_send(esi::processor::Job::Killmails)
+        .queue
+        .send(esi::processor::Job::Killmails)
+        .await
         .map_err(|e| {
             tracing::error!(error = e.to_string(), "Failed to enqueue refresh job");
             (
@@ -127,10 +170,146 @@ pub async fn killmails(State(state): State<AppState>) -> impl IntoResponse {
     )
 }
 
+/// Lists every tracked (authenticated) character. Reads `users` directly
+/// rather than going through the job queue. Responses are redacted to
+/// [`storage::models::UserSummary`] so OAuth tokens never leave the process.
+pub async fn list_users(State(state): State<AppState>) -> impl IntoResponse {
+    match storage::handlers::list_users(&state.pool).await {
+        Ok(users) => {
+            let summaries: Vec<storage::models::UserSummary> =
+                users.into_iter().map(Into::into).collect();
+            (
+                StatusCode::OK,
+                [("Content-Type", "application/json")],
+                serde_json::to_string(&summaries).unwrap_or_default(),
+            )
+        }
+        Err(e) => {
+            tracing::error!(error = e.to_string(), "Failed to list users");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [("Content-Type", "application/json")],
+                "".to_string(),
+            )
+        }
+    }
+}
+
+/// `404` if no row was removed, `204` otherwise - split out from
+/// `delete_user` so the status-code decision is unit-testable without a DB.
+fn delete_status(rows_affected: usize) -> StatusCode {
+    if rows_affected == 0 {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::NO_CONTENT
+    }
+}
+
+/// Stops tracking a character. `404` if no such character is tracked.
+pub async fn delete_user(
+    State(state): State<AppState>,
+    Path(character_id): Path<i64>,
+) -> impl IntoResponse {
+    match storage::handlers::delete_user(&state.pool, character_id).await {
+        Ok(rows_affected) => (
+            delete_status(rows_affected),
+            [("Content-Type", "application/json")],
+            "".to_string(),
+        ),
+        Err(e) => {
+            tracing::error!(character_id, error = e.to_string(), "Failed to delete user");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [("Content-Type", "application/json")],
+                "".to_string(),
+            )
+        }
+    }
+}
+
+/// Parses and clamps `?limit=`/`?offset=` (default 50, capped at 1..=200,
+/// and 0.. respectively), pulled out of `list_killmails` so the clamping is
+/// unit-testable without a DB.
+fn parse_pagination(params: &HashMap<String, String>) -> (i64, i64) {
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(50)
+        .clamp(1, 200);
+    let offset = params
+        .get("offset")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+        .max(0);
+    (limit, offset)
+}
+
+/// Lists stored killmails, newest first. `?status=` filters (`new`,
+/// `resolving`, `resolved`, `failed`); `?limit=` (default 50, capped at 200)
+/// and `?offset=` page through the results.
+pub async fn list_killmails(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let status = params.get("status").cloned();
+    let (limit, offset) = parse_pagination(&params);
+
+    match storage::handlers::list_killmails(&state.pool, status, limit, offset).await {
+        Ok(killmails) => (
+            StatusCode::OK,
+            [("Content-Type", "application/json")],
+            serde_json::to_string(&killmails).unwrap_or_default(),
+        ),
+        Err(e) => {
+            tracing::error!(error = e.to_string(), "Failed to list killmails");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [("Content-Type", "application/json")],
+                "".to_string(),
+            )
+        }
+    }
+}
+
+/// `200` if the row was found, `404` otherwise - split out from
+/// `get_killmail` so the status-code decision is unit-testable without a DB.
+fn found_status<T>(row: &Option<T>) -> StatusCode {
+    if row.is_some() {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Fetches a single stored killmail by id. `404` if it isn't stored.
+pub async fn get_killmail(
+    State(state): State<AppState>,
+    Path(killmail_id): Path<i64>,
+) -> impl IntoResponse {
+    match storage::handlers::get_killmail(&state.pool, killmail_id).await {
+        Ok(killmail) => (
+            found_status(&killmail),
+            [("Content-Type", "application/json")],
+            killmail
+                .map(|k| serde_json::to_string(&k).unwrap_or_default())
+                .unwrap_or_default(),
+        ),
+        Err(e) => {
+            tracing::error!(killmail_id, error = e.to_string(), "Failed to fetch killmail");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [("Content-Type", "application/json")],
+                "".to_string(),
+            )
+        }
+    }
+}
+
 pub async fn resolve(State(state): State<AppState>) -> impl IntoResponse {
     state
-        .jobs_sender
-        .try_send(esi::processor::Job::ResolveKillmails)
+        .queue
+        .send(esi::processor::Job::ResolveKillmails)
+        .await
         .map_err(|e| {
             tracing::error!(error = e.to_string(), "Failed to enqueue refresh job");
             (
@@ -147,3 +326,61 @@ pub async fn resolve(State(state): State<AppState>) -> impl IntoResponse {
         "".to_string(),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delete_status_is_404_when_nothing_was_removed() {
+        assert_eq!(delete_status(0), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn delete_status_is_204_when_a_row_was_removed() {
+        assert_eq!(delete_status(1), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn found_status_is_200_for_some_and_404_for_none() {
+        assert_eq!(found_status(&Some(())), StatusCode::OK);
+        assert_eq!(found_status::<()>(&None), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn parse_pagination_defaults_to_fifty_and_zero() {
+        let params = HashMap::new();
+        assert_eq!(parse_pagination(&params), (50, 0));
+    }
+
+    #[test]
+    fn parse_pagination_clamps_limit_to_one_through_two_hundred() {
+        let mut params = HashMap::new();
+        params.insert("limit".to_string(), "0".to_string());
+        assert_eq!(parse_pagination(&params).0, 1);
+
+        params.insert("limit".to_string(), "10000".to_string());
+        assert_eq!(parse_pagination(&params).0, 200);
+
+        params.insert("limit".to_string(), "20".to_string());
+        assert_eq!(parse_pagination(&params).0, 20);
+    }
+
+    #[test]
+    fn parse_pagination_clamps_offset_to_non_negative() {
+        let mut params = HashMap::new();
+        params.insert("offset".to_string(), "-5".to_string());
+        assert_eq!(parse_pagination(&params).1, 0);
+
+        params.insert("offset".to_string(), "100".to_string());
+        assert_eq!(parse_pagination(&params).1, 100);
+    }
+
+    #[test]
+    fn parse_pagination_ignores_unparseable_values() {
+        let mut params = HashMap::new();
+        params.insert("limit".to_string(), "not-a-number".to_string());
+        params.insert("offset".to_string(), "also-not-a-number".to_string());
+        assert_eq!(parse_pagination(&params), (50, 0));
+    }
+}
@@ -1,19 +1,35 @@
 use crate::esi;
+use crate::notifier::KillmailEvent;
+use crate::queue::JobQueue;
+use crate::storage::handlers::DbPool;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub jobs_sender: tokio::sync::mpsc::Sender<esi::processor::Job>,
+    /// Read-only management endpoints (`GET /users`, `GET /killmails`, ...)
+    /// query this directly rather than going through the job queue.
+    pub pool: DbPool,
+    pub queue: JobQueue,
     pub client: esi::EsiClient,
+    pub killmail_events: tokio::sync::broadcast::Sender<KillmailEvent>,
+    /// Shared secret the management API (`/users*`, `/killmails*`) requires
+    /// as a `Bearer` token; see `http::auth::require_admin_token`.
+    pub admin_token: std::sync::Arc<String>,
 }
 
 impl AppState {
     pub fn new(
-        jobs_sender: tokio::sync::mpsc::Sender<esi::processor::Job>,
+        pool: DbPool,
+        queue: JobQueue,
         client: &esi::EsiClient,
+        killmail_events: tokio::sync::broadcast::Sender<KillmailEvent>,
+        admin_token: String,
     ) -> Self {
         Self {
-            jobs_sender,
+            pool,
+            queue,
             client: client.clone(),
+            killmail_events,
+            admin_token: std::sync::Arc::new(admin_token),
         }
     }
 }
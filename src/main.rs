@@ -1,18 +1,35 @@
 use std::time::Duration;
 
-use axum::{Router, routing::get};
+use axum::{
+    Router,
+    routing::{delete, get},
+};
+use clap::Parser;
 use diesel::{PgConnection, r2d2::ConnectionManager};
+use diesel_async::AsyncPgConnection;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use r2d2::Pool;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use mkb::{
-    esi::{self, processor::Job},
+    esi,
     http::{handlers, state::AppState},
+    queue::JobQueue,
+    storage::handlers::DbPool,
 };
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations");
 
+#[derive(Parser)]
+#[command(name = "mkb", about = "Killboard server")]
+struct Args {
+    /// Apply pending migrations on startup before serving traffic
+    #[arg(long)]
+    auto_migrate: bool,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     dotenvy::dotenv().ok();
@@ -24,29 +41,97 @@ async fn main() -> Result<(), anyhow::Error> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let args = Args::parse();
     let config = mkb::config::Config::from_env();
 
+    // A small synchronous pool, used only for the startup migration check and
+    // (optionally) the DB config source below; the hot path runs on the async
+    // pool further down.
     let manager = ConnectionManager::<PgConnection>::new(config.database_uri.clone());
-    let pool = Pool::builder()
+    let migration_pool = Pool::builder()
+        .max_size(2)
         .build(manager)
         .expect("Failed to create pool.");
 
-    let (jobs_sender, jobs_receiver) = tokio::sync::mpsc::channel(10000);
-
-    let client = esi::EsiClient::from_config(&jobs_sender, config.clone());
-
-    let mut conn = pool.get()?;
-    conn.run_pending_migrations(MIGRATIONS)
-        .map_err(|e| anyhow::format_err!("failed to apply migrations: {e}"))?;
-
-    let processor = esi::processor::Processor::new(pool, &client);
-    let _ = processor.start(jobs_receiver).await;
+    let config = if std::env::var("MKB_CONFIG_SOURCE").as_deref() == Ok("db") {
+        mkb::config::Config::from_db(
+            config.database_uri.clone(),
+            migration_pool.clone(),
+            Duration::from_secs(config.poll_interval_secs()),
+        )
+        .expect("failed to load configuration from database")
+    } else {
+        config
+    };
+
+    if args.auto_migrate {
+        let mut conn = migration_pool.get()?;
+        let applied = conn
+            .run_pending_migrations(MIGRATIONS)
+            .map_err(|e| anyhow::format_err!("failed to apply migrations: {e}"))?;
+        for migration in applied {
+            tracing::info!(%migration, "applied migration");
+        }
+    }
 
-    let state = AppState::new(jobs_sender.clone(), &client);
+    // Async pool used by the job queue and processor, capped well under
+    // Postgres' default connection limit so a burst of saves can't starve
+    // other services sharing the database. Connections are natively async
+    // (`diesel_async`), so queries run as plain `.await`s rather than being
+    // offloaded to a blocking thread pool.
+    let async_manager =
+        AsyncDieselConnectionManager::<AsyncPgConnection>::new(config.database_uri.clone());
+    let async_pool = DbPool::builder(async_manager)
+        .max_size(10)
+        .build()
+        .map_err(|e| anyhow::format_err!("failed to create async pool: {e}"))?;
+
+    // Jobs are rows in the `jobs` table rather than an in-memory channel, so
+    // queued work survives a restart; a dedicated LISTEN connection wakes
+    // idle workers as soon as something is enqueued.
+    let queue = JobQueue::new(async_pool.clone());
+    let job_notify = mkb::queue::listen(&config.database_uri).await?;
+
+    let client = esi::EsiClient::from_config(queue.clone(), config.clone());
+
+    let (killmail_events, _) = tokio::sync::broadcast::channel(1024);
+
+    // A single token shared by the scheduler, the processor, and the Axum
+    // server, so a SIGINT/SIGTERM drains all three in lockstep instead of
+    // the old racy sleep-then-abort sequence.
+    let shutdown = CancellationToken::new();
+
+    let processor = esi::processor::Processor::new(
+        async_pool.clone(),
+        &client,
+        queue.clone(),
+        killmail_events.clone(),
+    );
+    let processor_handle = processor.start(job_notify, shutdown.clone()).await;
+
+    let admin_token = std::env::var("MKB_ADMIN_TOKEN")
+        .expect("MKB_ADMIN_TOKEN environment variable not set (required to guard the management API)");
+
+    let state = AppState::new(async_pool, queue.clone(), &client, killmail_events, admin_token);
 
-    let (scheduler_stop_tx, scheduler_stop_rx) = tokio::sync::oneshot::channel();
     let scheduler_handle =
-        mkb::esi::scheduler::start_scheduler(scheduler_stop_rx, jobs_sender.clone()).await;
+        mkb::esi::scheduler::start_scheduler(shutdown.clone(), queue.clone()).await;
+
+    // The management API reads/deletes tracked characters and killmails, so
+    // it sits behind `MKB_ADMIN_TOKEN`, unlike the public routes below.
+    // Path params use axum 0.8's `{param}` syntax - the older `:param` form
+    // panics at router-build time on 0.8+ rather than just being deprecated,
+    // and `require_admin_token`'s non-generic `Request`/`Next` signature
+    // (`f590e4d`) already commits this tree to 0.7+.
+    let management_routes = Router::new()
+        .route("/users", get(handlers::list_users))
+        .route("/users/{character_id}", delete(handlers::delete_user))
+        .route("/killmails", get(handlers::list_killmails))
+        .route("/killmails/{id}", get(handlers::get_killmail))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            mkb::http::auth::require_admin_token,
+        ));
 
     // Build a simple Axum app
     let app = Router::new()
@@ -56,22 +141,50 @@ async fn main() -> Result<(), anyhow::Error> {
         .route("/testing/refresh", get(handlers::refresh))
         .route("/testing/killmails", get(handlers::killmails))
         .route("/testing/resolve", get(handlers::resolve))
+        .route("/ws/killmails", get(handlers::ws_killmails))
+        .merge(management_routes)
         .with_state(state);
 
     tracing::info!("starting server... http://localhost:3000/auth");
 
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(wait_for_shutdown_signal(shutdown.clone()))
+        .await?;
 
-    jobs_sender.send(Job::Stop).await?;
+    let _ = scheduler_handle.await;
+    let _ = processor_handle.await;
 
-    let _ = scheduler_stop_tx.send(());
-    tokio::time::interval(Duration::from_secs(3)).tick().await;
-    if !scheduler_handle.is_finished() {
-        scheduler_handle.abort();
-        let _ = scheduler_handle.await;
+    Ok(())
+}
+
+/// Resolves on SIGINT or SIGTERM, cancelling `shutdown` so the scheduler and
+/// processor start draining at the same moment Axum stops accepting new
+/// connections.
+async fn wait_for_shutdown_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 
-    Ok(())
+    tracing::info!("shutdown signal received, draining in-flight work");
+    shutdown.cancel();
 }
@@ -0,0 +1,90 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+
+use crate::config::ConfigValues;
+
+/// Broadcast to `AppState::killmail_events` subscribers (the `/ws/killmails`
+/// handler) whenever a killmail transitions to `resolved`. `entity_ids`
+/// carries everything the killmail touched (solar system, victim,
+/// attackers, ships) so subscribers can filter without a second query.
+#[derive(Clone, Debug, Serialize)]
+pub struct KillmailEvent {
+    pub killmail_id: i64,
+    pub killmail_hash: String,
+    pub entity_ids: Vec<i64>,
+}
+
+impl KillmailEvent {
+    /// Whether this event should be delivered to a subscriber filtering on
+    /// `entity_id` (e.g. a tracked `corporation_id` or `ship_type_id`).
+    pub fn matches(&self, entity_id: i64) -> bool {
+        self.entity_ids.contains(&entity_id)
+    }
+}
+
+/// Optional SMTP backend for emailing users who registered
+/// `users.notify_email`. No-op (via `EmailNotifier::from_config` returning
+/// `None`) unless `smtp_host`, `smtp_user`, `smtp_pass`, and `from_addr` are
+/// all set, so existing deployments are unaffected.
+///
+/// Uses `AsyncSmtpTransport` rather than the blocking `SmtpTransport` - this
+/// is called directly from `Processor::run`, and a blocking send would stall
+/// every other queued job behind a slow or hung SMTP server.
+pub struct EmailNotifier {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl EmailNotifier {
+    pub fn from_config(values: &ConfigValues) -> Option<Self> {
+        let host = values.smtp_host.as_ref()?;
+        let user = values.smtp_user.as_ref()?;
+        let pass = values.smtp_pass.as_ref()?;
+        let from = values.from_addr.as_ref()?;
+
+        let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+            .ok()?
+            .credentials(Credentials::new(user.clone(), pass.clone()))
+            .build();
+        let from = from.parse().ok()?;
+
+        Some(Self { mailer, from })
+    }
+
+    pub async fn notify(&self, to: &str, event: &KillmailEvent) -> Result<(), anyhow::Error> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(format!("New killmail {}", event.killmail_id))
+            .body(format!(
+                "Killmail {} ({}) just resolved.",
+                event.killmail_id, event.killmail_hash
+            ))?;
+
+        self.mailer
+            .send(message)
+            .await
+            .map_err(|e| anyhow::format_err!("failed to send notification email: {e}"))?;
+        Ok(())
+    }
+
+    /// Short summary sent as soon as a killmail is persisted, well before
+    /// resolution fills in entity names - so it can only reference the id.
+    pub async fn notify_new_killmail(&self, to: &str, killmail_id: i64) -> Result<(), anyhow::Error> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(format!("New killmail {killmail_id}"))
+            .body(format!(
+                "A new killmail ({killmail_id}) was just recorded for your character."
+            ))?;
+
+        self.mailer
+            .send(message)
+            .await
+            .map_err(|e| anyhow::format_err!("failed to send notification email: {e}"))?;
+        Ok(())
+    }
+}
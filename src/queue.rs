@@ -0,0 +1,96 @@
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+use crate::esi::processor::Job;
+use crate::storage::handlers::{self, DbPool};
+
+/// Postgres `NOTIFY`/`LISTEN` channel workers wake up on when a job is
+/// enqueued, instead of waiting for the fallback poll timer.
+pub const CHANNEL: &str = "mkb_jobs";
+
+/// A durable stand-in for the old in-memory `mpsc::Sender<Job>`: `send`
+/// writes a row to the `jobs` table instead of buffering in a channel, so
+/// queued work survives a restart or crash, and a worker claims it with
+/// `claim` rather than receiving it off a channel.
+#[derive(Clone)]
+pub struct JobQueue {
+    pool: DbPool,
+}
+
+impl JobQueue {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// Enqueues `job`, returning the id of the new row.
+    pub async fn send(&self, job: Job) -> Result<Uuid, anyhow::Error> {
+        let payload = serde_json::to_value(&job)?;
+        handlers::enqueue_job(&self.pool, job.kind().to_string(), payload).await
+    }
+
+    /// Claims up to `limit` ready jobs, skipping any a payload fails to
+    /// decode (logged, not retried - a bad payload won't fix itself).
+    pub async fn claim(&self, limit: i64) -> Result<Vec<(Uuid, Job)>, anyhow::Error> {
+        let claimed = handlers::claim_jobs(&self.pool, limit).await?;
+        Ok(claimed
+            .into_iter()
+            .filter_map(|row| match serde_json::from_value(row.payload.clone()) {
+                Ok(job) => Some((row.id, job)),
+                Err(e) => {
+                    tracing::error!(
+                        id = %row.id,
+                        kind = row.kind,
+                        error = e.to_string(),
+                        "failed to decode job payload"
+                    );
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub async fn complete(&self, id: Uuid) -> Result<(), anyhow::Error> {
+        handlers::complete_job(&self.pool, id).await?;
+        Ok(())
+    }
+
+    pub async fn fail(
+        &self,
+        id: Uuid,
+        base: chrono::Duration,
+        cap: chrono::Duration,
+        max_attempts: i32,
+    ) -> Result<(), anyhow::Error> {
+        handlers::fail_job(&self.pool, id, base, cap, max_attempts).await?;
+        Ok(())
+    }
+}
+
+/// Opens a dedicated connection that `LISTEN`s on [`CHANNEL`] and forwards a
+/// wakeup for every notification received. The receiver only ever needs to
+/// know "something changed" - it re-polls the table regardless - so bursts
+/// of notifications are coalesced via `try_send`.
+pub async fn listen(database_uri: &str) -> Result<tokio::sync::mpsc::Receiver<()>, anyhow::Error> {
+    let (client, mut connection) =
+        tokio_postgres::connect(database_uri, tokio_postgres::NoTls).await?;
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(tokio_postgres::AsyncMessage::Notification(_)) => {
+                    let _ = tx.try_send(());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(error = e.to_string(), "job queue listener connection failed");
+                    break;
+                }
+            }
+        }
+    });
+
+    client.batch_execute(&format!("LISTEN {CHANNEL}")).await?;
+    Ok(rx)
+}
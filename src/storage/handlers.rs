@@ -1,14 +1,20 @@
 use super::{models, schema};
 use diesel::insert_into;
 use diesel::prelude::*;
-use diesel::{PgConnection, r2d2::ConnectionManager};
-use r2d2::Pool;
-
-pub fn save_user(
-    pool: &Pool<ConnectionManager<PgConnection>>,
-    user: models::User,
-) -> Result<usize, diesel::result::Error> {
-    let mut conn = pool.get().unwrap();
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+pub type DbPool = Pool<AsyncPgConnection>;
+
+async fn checkout(pool: &DbPool) -> Result<deadpool::managed::Object<diesel_async::pooled_connection::AsyncDieselConnectionManager<AsyncPgConnection>>, anyhow::Error> {
+    pool.get()
+        .await
+        .map_err(|e| anyhow::format_err!("failed to check out connection: {e}"))
+}
+
+pub async fn save_user(pool: &DbPool, user: models::User) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
     insert_into(schema::users::table)
         .values(&user)
         .on_conflict(schema::users::character_id)
@@ -18,39 +24,518 @@ pub fn save_user(
             schema::users::refresh_token.eq(&user.refresh_token),
             schema::users::expires_at.eq(&user.expires_at),
             schema::users::updated_at.eq(chrono::Utc::now()),
+            schema::users::corporation_id.eq(&user.corporation_id),
         ))
         .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
 }
 
-pub fn save_killmail(
-    pool: &Pool<ConnectionManager<PgConnection>>,
-    killmail: models::Killmail,
-) -> Result<usize, diesel::result::Error> {
-    let mut conn = pool.get().unwrap();
+pub async fn save_killmail(pool: &DbPool, killmail: models::Killmail) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
     insert_into(schema::killmails::table)
         .values(&killmail)
         .on_conflict_do_nothing()
         .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
 }
 
-pub fn set_killmail_status(
-    pool: &Pool<ConnectionManager<PgConnection>>,
+pub async fn set_killmail_status(
+    pool: &DbPool,
     killmail_id: i64,
-    status: &str,
-) -> Result<usize, diesel::result::Error> {
-    let mut conn = pool.get().unwrap();
+    status: String,
+) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
     diesel::update(schema::killmails::table.filter(schema::killmails::killmail_id.eq(killmail_id)))
         .set(schema::killmails::status.eq(status))
         .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
 }
 
-pub fn save_entity(
-    pool: &Pool<ConnectionManager<PgConnection>>,
-    entity: models::Entity,
-) -> Result<usize, diesel::result::Error> {
-    let mut conn = pool.get().unwrap();
+pub async fn save_entity(pool: &DbPool, entity: models::Entity) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
     insert_into(schema::entities::table)
         .values(&entity)
         .on_conflict_do_nothing()
         .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Entities inserted as placeholders (empty `name`) when a killmail
+/// referenced an id ESI hasn't looked up yet, and so are due for a
+/// `/universe/names/` lookup.
+pub async fn unresolved_entity_ids(pool: &DbPool, limit: i64) -> Result<Vec<i64>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    schema::entities::table
+        .filter(schema::entities::name.eq(""))
+        .select(schema::entities::id)
+        .limit(limit)
+        .load::<i64>(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Fills in the real name/category for a previously-placeholder entity.
+pub async fn resolve_entity(
+    pool: &DbPool,
+    id: i64,
+    name: String,
+    type_: String,
+) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    diesel::update(schema::entities::table.filter(schema::entities::id.eq(id)))
+        .set((schema::entities::name.eq(name), schema::entities::type_.eq(type_)))
+        .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+pub async fn save_error(pool: &DbPool, context: String, detail: String) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    insert_into(schema::errors::table)
+        .values(models::Error::new(context, detail))
+        .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Selects killmails that are due for resolution (`new`/`failed`, past their
+/// `next_retry_at`, and under the attempt cap), flips them to `resolving`,
+/// and returns the claimed rows so the caller can enqueue a `Job::Killmail`
+/// for each.
+pub async fn claim_killmails_for_resolution(
+    pool: &DbPool,
+    max_attempts: i32,
+    limit: i64,
+) -> Result<Vec<models::Killmail>, anyhow::Error> {
+    use diesel::dsl::now;
+
+    let mut conn = checkout(pool).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let claimed = schema::killmails::table
+                .filter(
+                    schema::killmails::status
+                        .eq("new")
+                        .or(schema::killmails::status.eq("failed")),
+                )
+                .filter(schema::killmails::next_retry_at.le(now))
+                .filter(schema::killmails::attempts.lt(max_attempts))
+                .limit(limit)
+                .load::<models::Killmail>(conn)
+                .await?;
+
+            let ids: Vec<i64> = claimed.iter().map(|km| km.killmail_id).collect();
+            diesel::update(
+                schema::killmails::table.filter(schema::killmails::killmail_id.eq_any(&ids)),
+            )
+            .set(schema::killmails::status.eq("resolving"))
+            .execute(conn)
+            .await?;
+
+            Ok(claimed)
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+pub async fn mark_killmail_resolved(pool: &DbPool, killmail_id: i64) -> Result<usize, anyhow::Error> {
+    set_killmail_status(pool, killmail_id, "resolved".to_string()).await
+}
+
+/// `min(base * 2^attempts, cap)`, the exponential backoff shared by killmail
+/// retries (`mark_killmail_failed`) and job retries (`fail_job`). A pure
+/// function so the doubling/capping logic is unit-testable without a DB.
+pub(crate) fn backoff_delay(
+    attempts: i32,
+    base: chrono::Duration,
+    cap: chrono::Duration,
+) -> chrono::Duration {
+    base.checked_mul(1 << attempts.clamp(0, 30))
+        .unwrap_or(cap)
+        .min(cap)
+}
+
+/// Records the failure and reschedules the killmail with exponential
+/// backoff: `next_retry_at = now() + min(base * 2^attempts, cap)`.
+pub async fn mark_killmail_failed(
+    pool: &DbPool,
+    killmail_id: i64,
+    base: chrono::Duration,
+    cap: chrono::Duration,
+) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let attempts = schema::killmails::table
+                .find(killmail_id)
+                .select(schema::killmails::attempts)
+                .first::<i32>(conn)
+                .await?;
+
+            let delay = backoff_delay(attempts, base, cap);
+            let next_retry_at = chrono::Utc::now() + delay;
+
+            diesel::update(
+                schema::killmails::table.filter(schema::killmails::killmail_id.eq(killmail_id)),
+            )
+            .set((
+                schema::killmails::status.eq("failed"),
+                schema::killmails::attempts.eq(attempts + 1),
+                schema::killmails::next_retry_at.eq(next_retry_at),
+            ))
+            .execute(conn)
+            .await
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Records which character already got the "new killmail" email for this
+/// kill, so the later "resolved" email (sent once `entity_ids` are known)
+/// can skip notifying them again for the same kill.
+pub async fn mark_killmail_notified(
+    pool: &DbPool,
+    killmail_id: i64,
+    character_id: i64,
+) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    diesel::update(schema::killmails::table.filter(schema::killmails::killmail_id.eq(killmail_id)))
+        .set(schema::killmails::notified_character_id.eq(character_id))
+        .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Records that `entity_id` appeared on `killmail_id` as `entity_side`, so
+/// `killmail_entity_ids` can find it later. Upserts a placeholder `entities`
+/// row for `entity_id` in the same transaction first, since
+/// `killmails_x_entities.entity_id` has a hard FK to `entities`: this job is
+/// enqueued independently of (and racing) the `Job::SaveEntity` job for the
+/// same entity, and the two must not depend on each other's ordering or
+/// success to avoid losing the link if `SaveEntity` retries or dead-letters
+/// first.
+pub async fn save_killmail_entity(
+    pool: &DbPool,
+    killmail_id: i64,
+    entity_id: i64,
+    entity_type: String,
+    entity_side: String,
+) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            insert_into(schema::entities::table)
+                .values(models::Entity {
+                    id: entity_id,
+                    name: "".to_string(),
+                    type_: entity_type,
+                })
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
+
+            insert_into(schema::killmails_x_entities::table)
+                .values(models::KillmailEntity::new(killmail_id, entity_id, entity_side))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// The entity ids (solar system, victim, attackers, ships, ...) recorded
+/// against a killmail, used to decide which WebSocket subscribers and email
+/// recipients should hear about it.
+pub async fn killmail_entity_ids(pool: &DbPool, killmail_id: i64) -> Result<Vec<i64>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    schema::killmails_x_entities::table
+        .filter(schema::killmails_x_entities::killmail_id.eq(killmail_id))
+        .select(schema::killmails_x_entities::entity_id)
+        .load::<i64>(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Inserts a `ready` job row and wakes any idle workers via `NOTIFY
+/// mkb_jobs`, so a worker blocked on `LISTEN` picks it up without waiting
+/// for the fallback poll timer.
+pub async fn enqueue_job(
+    pool: &DbPool,
+    kind: String,
+    payload: serde_json::Value,
+) -> Result<uuid::Uuid, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let record = models::JobRecord::new(kind, payload);
+            insert_into(schema::jobs::table)
+                .values(&record)
+                .execute(conn)
+                .await?;
+            diesel::sql_query("NOTIFY mkb_jobs").execute(conn).await?;
+            Ok(record.id)
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Claims up to `limit` ready jobs with `FOR UPDATE SKIP LOCKED` so multiple
+/// workers never race on the same row, and flips them to `running`.
+pub async fn claim_jobs(pool: &DbPool, limit: i64) -> Result<Vec<models::JobRecord>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let claimed = schema::jobs::table
+                .filter(schema::jobs::status.eq("ready"))
+                .filter(schema::jobs::next_run.le(diesel::dsl::now))
+                .order(schema::jobs::next_run.asc())
+                .limit(limit)
+                .for_update()
+                .skip_locked()
+                .load::<models::JobRecord>(conn)
+                .await?;
+
+            let ids: Vec<uuid::Uuid> = claimed.iter().map(|job| job.id).collect();
+            diesel::update(schema::jobs::table.filter(schema::jobs::id.eq_any(&ids)))
+                .set((
+                    schema::jobs::status.eq("running"),
+                    schema::jobs::locked_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)
+                .await?;
+
+            Ok(claimed)
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+pub async fn complete_job(pool: &DbPool, job_id: uuid::Uuid) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    diesel::update(schema::jobs::table.filter(schema::jobs::id.eq(job_id)))
+        .set(schema::jobs::status.eq("done"))
+        .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Reschedules a failed job with exponential backoff
+/// (`next_run = now() + min(base * 2^attempts, cap)`), or parks it as `dead`
+/// once its attempt count reaches `max_attempts` so an operator can inspect it.
+pub async fn fail_job(
+    pool: &DbPool,
+    job_id: uuid::Uuid,
+    base: chrono::Duration,
+    cap: chrono::Duration,
+    max_attempts: i32,
+) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let attempts = schema::jobs::table
+                .find(job_id)
+                .select(schema::jobs::attempts)
+                .first::<i32>(conn)
+                .await?
+                + 1;
+
+            if attempts >= max_attempts {
+                return diesel::update(schema::jobs::table.filter(schema::jobs::id.eq(job_id)))
+                    .set((
+                        schema::jobs::status.eq("dead"),
+                        schema::jobs::attempts.eq(attempts),
+                    ))
+                    .execute(conn)
+                    .await;
+            }
+
+            let delay = backoff_delay(attempts, base, cap);
+            let next_run = chrono::Utc::now() + delay;
+
+            diesel::update(schema::jobs::table.filter(schema::jobs::id.eq(job_id)))
+                .set((
+                    schema::jobs::status.eq("ready"),
+                    schema::jobs::attempts.eq(attempts),
+                    schema::jobs::next_run.eq(next_run),
+                ))
+                .execute(conn)
+                .await
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Tracked users whose character is among `character_ids` and who have
+/// opted into email notifications.
+pub async fn users_to_notify(
+    pool: &DbPool,
+    character_ids: Vec<i64>,
+) -> Result<Vec<models::User>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    schema::users::table
+        .filter(schema::users::character_id.eq_any(character_ids))
+        .filter(schema::users::notify_email.is_not_null())
+        .load::<models::User>(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+pub async fn list_users(pool: &DbPool) -> Result<Vec<models::User>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    schema::users::table
+        .order(schema::users::character_id.asc())
+        .load::<models::User>(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+/// Stops tracking the character, returning the number of rows removed (0 if
+/// no such character was tracked).
+pub async fn delete_user(pool: &DbPool, character_id: i64) -> Result<usize, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    diesel::delete(schema::users::table.filter(schema::users::character_id.eq(character_id)))
+        .execute(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+pub async fn list_killmails(
+    pool: &DbPool,
+    status: Option<String>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<models::Killmail>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    let mut query = schema::killmails::table.into_boxed();
+    if let Some(status) = status {
+        query = query.filter(schema::killmails::status.eq(status));
+    }
+
+    query
+        .order(schema::killmails::killmail_id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load::<models::Killmail>(&mut conn)
+        .await
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+pub async fn get_killmail(
+    pool: &DbPool,
+    killmail_id: i64,
+) -> Result<Option<models::Killmail>, anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    schema::killmails::table
+        .find(killmail_id)
+        .first::<models::Killmail>(&mut conn)
+        .await
+        .optional()
+        .map_err(|e| anyhow::format_err!("query failed: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+    use std::time::{Duration, Instant};
+
+    /// A burst of killmail saves should run on their own pooled connections
+    /// rather than blocking a Tokio worker thread, so a concurrently spawned
+    /// "ESI fetch" task keeps making progress instead of queueing up behind
+    /// the saves. Requires `DATABASE_URL` to point at a real Postgres
+    /// instance; skipped otherwise since this is exercising pool behavior,
+    /// not query correctness.
+    #[tokio::test]
+    async fn save_burst_does_not_starve_other_tasks() {
+        let Ok(database_uri) = std::env::var("DATABASE_URL") else {
+            eprintln!("skipping: DATABASE_URL not set");
+            return;
+        };
+
+        let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(database_uri);
+        let pool = DbPool::builder(manager).max_size(4).build().unwrap();
+
+        let ticks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ticks_clone = ticks.clone();
+        let ticker = tokio::spawn(async move {
+            for _ in 0..20 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                ticks_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+
+        let start = Instant::now();
+        let mut saves = tokio::task::JoinSet::new();
+        for i in 0..50 {
+            let pool = pool.clone();
+            saves.spawn(async move {
+                let _ = save_killmail(
+                    &pool,
+                    models::Killmail {
+                        killmail_id: 1_000_000 + i,
+                        killmail_hash: "benchmark".to_string(),
+                        status: "new".to_string(),
+                        attempts: 0,
+                        next_retry_at: chrono::Utc::now(),
+                        notified_character_id: None,
+                    },
+                )
+                .await;
+            });
+        }
+        saves.join_all().await;
+        let _ = ticker.await;
+
+        assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) >= 15);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn backoff_delay_doubles_per_attempt() {
+        let base = chrono::Duration::seconds(30);
+        let cap = chrono::Duration::hours(1);
+
+        assert_eq!(backoff_delay(0, base, cap), chrono::Duration::seconds(30));
+        assert_eq!(backoff_delay(1, base, cap), chrono::Duration::seconds(60));
+        assert_eq!(backoff_delay(2, base, cap), chrono::Duration::seconds(120));
+        assert_eq!(backoff_delay(3, base, cap), chrono::Duration::seconds(240));
+    }
+
+    #[test]
+    fn backoff_delay_is_capped() {
+        let base = chrono::Duration::seconds(30);
+        let cap = chrono::Duration::hours(1);
+
+        // 30s * 2^10 would blow past an hour without the cap.
+        assert_eq!(backoff_delay(10, base, cap), cap);
+        assert_eq!(backoff_delay(30, base, cap), cap);
+    }
+
+    #[test]
+    fn backoff_delay_clamps_out_of_range_attempts() {
+        let base = chrono::Duration::seconds(30);
+        let cap = chrono::Duration::hours(1);
+
+        // Negative attempts shouldn't underflow the shift; a huge attempt
+        // count shouldn't overflow `checked_mul` into a panic or bogus value.
+        assert_eq!(backoff_delay(-5, base, cap), base);
+        assert_eq!(backoff_delay(i32::MAX, base, cap), cap);
+    }
 }
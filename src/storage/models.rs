@@ -1,14 +1,72 @@
 use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::esi::Claims;
 
-#[derive(Clone, Debug, Queryable, Insertable, Selectable)]
+#[derive(Clone, Debug, Serialize, Deserialize, Queryable, Insertable, Selectable)]
 #[diesel(table_name = super::schema::killmails)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Killmail {
     pub killmail_id: i64,
     pub killmail_hash: String,
     pub status: String,
+    pub attempts: i32,
+    pub next_retry_at: chrono::DateTime<chrono::Utc>,
+    /// The character already sent a "new killmail" email for this kill (via
+    /// `Job::Notify`, right after it was saved) - set so the later
+    /// "resolved" email doesn't notify them again for the same kill.
+    pub notified_character_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable, Selectable)]
+#[diesel(table_name = super::schema::errors)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Error {
+    pub id: uuid::Uuid,
+    pub context: String,
+    pub detail: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Error {
+    pub fn new(context: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4(),
+            context: context.into(),
+            detail: detail.into(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// A row in the durable job queue: `kind`/`payload` are the serialized form
+/// of an `esi::processor::Job`, kept generic here so storage doesn't need to
+/// know about that type.
+#[derive(Clone, Debug, Queryable, Insertable, Selectable)]
+#[diesel(table_name = super::schema::jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct JobRecord {
+    pub id: uuid::Uuid,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_run: chrono::DateTime<chrono::Utc>,
+    pub locked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl JobRecord {
+    pub fn new(kind: impl Into<String>, payload: serde_json::Value) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4(),
+            kind: kind.into(),
+            payload,
+            status: "ready".to_string(),
+            attempts: 0,
+            next_run: chrono::Utc::now(),
+            locked_at: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Queryable, Insertable, Selectable)]
@@ -20,7 +78,32 @@ pub struct Entity {
     pub type_: String,
 }
 
+/// A join row recording that `entity_id` appeared on `killmail_id` as
+/// `entity_side` (e.g. `"victim_character"`, `"attacker_corporation"`,
+/// `"solar_system"`) - what `storage::handlers::killmail_entity_ids` reads
+/// back to drive WebSocket filtering and email notifications.
 #[derive(Clone, Debug, Queryable, Insertable, Selectable)]
+#[diesel(table_name = super::schema::killmails_x_entities)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct KillmailEntity {
+    pub id: uuid::Uuid,
+    pub killmail_id: i64,
+    pub entity_id: i64,
+    pub entity_side: String,
+}
+
+impl KillmailEntity {
+    pub fn new(killmail_id: i64, entity_id: i64, entity_side: impl Into<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4(),
+            killmail_id,
+            entity_id,
+            entity_side: entity_side.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Queryable, Insertable, Selectable)]
 #[diesel(table_name = super::schema::users)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct User {
@@ -32,6 +115,35 @@ pub struct User {
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
     pub last_fetched: Option<chrono::DateTime<chrono::Utc>>,
+    pub notify_email: Option<String>,
+    pub corporation_id: Option<i64>,
+}
+
+/// The subset of [`User`] safe to hand back over the management API.
+/// `access_token`/`refresh_token` are live EVE SSO credentials and must
+/// never leave the process, so `GET /users` serializes this instead of
+/// the storage model directly.
+#[derive(Clone, Debug, Serialize)]
+pub struct UserSummary {
+    pub character_id: i64,
+    pub corporation_id: Option<i64>,
+    pub notify_email: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub last_fetched: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<User> for UserSummary {
+    fn from(user: User) -> Self {
+        Self {
+            character_id: user.character_id,
+            corporation_id: user.corporation_id,
+            notify_email: user.notify_email,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            last_fetched: user.last_fetched,
+        }
+    }
 }
 
 impl User {
@@ -51,6 +163,8 @@ impl User {
             created_at: chrono::Utc::now(),
             updated_at: chrono::Utc::now(),
             last_fetched: None,
+            notify_email: None,
+            corporation_id: None,
         }
     }
 }
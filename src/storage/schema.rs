@@ -1,5 +1,12 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    config (key) {
+        key -> Text,
+        value -> Text,
+    }
+}
+
 diesel::table! {
     entities (id) {
         id -> Int8,
@@ -9,11 +16,37 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    errors (id) {
+        id -> Uuid,
+        context -> Text,
+        detail -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     killmails (killmail_id) {
         killmail_id -> Int8,
         killmail_hash -> Text,
         status -> Text,
+        attempts -> Int4,
+        next_retry_at -> Timestamptz,
+        notified_character_id -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+
+    jobs (id) {
+        id -> Uuid,
+        kind -> Text,
+        payload -> Jsonb,
+        status -> Text,
+        attempts -> Int4,
+        next_run -> Timestamptz,
+        locked_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -36,10 +69,20 @@ diesel::table! {
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
         last_fetched -> Nullable<Timestamptz>,
+        notify_email -> Nullable<Text>,
+        corporation_id -> Nullable<Int8>,
     }
 }
 
 diesel::joinable!(killmails_x_entities -> entities (entity_id));
 diesel::joinable!(killmails_x_entities -> killmails (killmail_id));
 
-diesel::allow_tables_to_appear_in_same_query!(entities, killmails, killmails_x_entities, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    config,
+    entities,
+    errors,
+    jobs,
+    killmails,
+    killmails_x_entities,
+    users,
+);